@@ -1,114 +1,754 @@
 use crate::network::message::Message;
 use core::panic;
-use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
 
 /// MessageTracker tracks a configurable fixed amount of messages.
-/// Messages are stored first-in-first-out.  
+/// Messages are stored first-in-first-out.
 /// Duplicate messages should not be stored in the queue.
 pub trait MessageTracker {
-    /// Add will add a message to the tracker, deleting the oldest message if necessary
-    fn add(&mut self, message: Message);
+    /// Add will add a message to the tracker, deleting the oldest message if
+    /// necessary. The outcome reports whether the message was stored, was a
+    /// duplicate (resident or recently seen), was rejected for exceeding its
+    /// peer's quota, or caused an eviction.
+    fn add(&mut self, message: Message) -> AddOutcome;
     /// Delete will delete message from tracker
     fn delete(&mut self, id: &str) -> Option<Message>;
-    /// Get returns a message for a given ID.  Message is retained in tracker
-    fn get(&self, id: &str) -> Option<Message>;
-    /// Messages returns messages in FIFO order
-    fn get_all(&self) -> Vec<Message>;
+    /// Get returns a message for a given ID.  Message is retained in tracker.
+    /// Expired messages are evicted on access and never returned.
+    fn get(&mut self, id: &str) -> Option<Message>;
+    /// Messages returns messages in FIFO order. Expired messages are
+    /// evicted as they are encountered and never returned.
+    fn get_all(&mut self) -> Vec<Message>;
+    /// Returns a single peer's messages in FIFO order.
+    fn get_by_peer(&self, peer_id: &str) -> Vec<Message>;
+    /// Deletes every message from a given peer, e.g. on disconnect, and
+    /// returns what was deleted in FIFO order.
+    fn delete_by_peer(&mut self, peer_id: &str) -> Vec<Message>;
+    /// Returns messages in FIFO order, restricted to the peers allowed by
+    /// `peers`.
+    fn get_all_filtered(&self, peers: &PeerFilter) -> Vec<Message>;
 }
 
-struct MessageStore {
-    queue: VecDeque<Message>,
-    // Mapping from message ID to queue index, works as a cache
+/// Selects which peers' messages a query should include.
+pub enum PeerFilter {
+    /// Only messages from these peers.
+    Only(HashSet<String>),
+    /// Messages from any peer except these.
+    Except(HashSet<String>),
+}
+
+impl PeerFilter {
+    fn matches(&self, peer_id: &str) -> bool {
+        match self {
+            PeerFilter::Only(peers) => peers.contains(peer_id),
+            PeerFilter::Except(peers) => !peers.contains(peer_id),
+        }
+    }
+}
+
+/// Result of an `add`, reported so callers can tell a message apart from a
+/// duplicate or a quota rejection instead of having both silently no-op.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AddOutcome {
+    /// The message was stored without evicting anything.
+    Stored,
+    /// The message's ID already existed in the tracker; nothing changed.
+    Duplicate,
+    /// The message's peer already holds `per_peer_limit` messages; the
+    /// message was rejected.
+    PeerQuotaExceeded,
+    /// The message was stored, and the returned message was evicted to make
+    /// room under the FIFO size limit.
+    Evicted(Message),
+    /// The message's fingerprint was found in the seen-cache, so it was
+    /// rejected even though it is no longer (or never was) resident in the
+    /// main queue.
+    RecentlySeen,
+}
+
+/// Selects what identifies a message for the seen-cache: its declared `id`,
+/// or a hash of its `data` so byte-identical messages with different IDs are
+/// still recognized as duplicates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupMode {
+    ById,
+    ByContentHash,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Fingerprint {
+    Id(String),
+    ContentHash(u64),
+}
+
+/// A bridge for pushing tracker activity into an external metrics system
+/// (e.g. `prometheus-client`) as it happens, in addition to the pull-style
+/// `MessageStore::metrics` snapshot.
+pub trait MetricsSink {
+    /// A message was stored.
+    fn on_added(&self, peer_id: &str, size: usize);
+    /// A message was rejected as a duplicate, resident or recently seen.
+    fn on_duplicate(&self);
+    /// A message was removed by an explicit `delete`/`delete_by_peer` call.
+    fn on_deleted(&self, peer_id: &str);
+    /// A message was removed automatically, by FIFO overflow or TTL expiry.
+    fn on_evicted(&self, peer_id: &str);
+}
+
+struct NoopMetricsSink;
+
+impl MetricsSink for NoopMetricsSink {
+    fn on_added(&self, _peer_id: &str, _size: usize) {}
+    fn on_duplicate(&self) {}
+    fn on_deleted(&self, _peer_id: &str) {}
+    fn on_evicted(&self, _peer_id: &str) {}
+}
+
+// Upper bound (in bytes) of each bucket in the message size histogram; the
+// final, implicit bucket holds anything larger than the last bound.
+const SIZE_HISTOGRAM_BOUNDS: [usize; 4] = [64, 256, 1024, 4096];
+
+#[derive(Default)]
+struct SizeHistogram {
+    counts: [u64; SIZE_HISTOGRAM_BOUNDS.len() + 1],
+}
+
+impl SizeHistogram {
+    fn record(&mut self, size: usize) {
+        let bucket = SIZE_HISTOGRAM_BOUNDS
+            .iter()
+            .position(|&bound| size <= bound)
+            .unwrap_or(SIZE_HISTOGRAM_BOUNDS.len());
+        self.counts[bucket] += 1;
+    }
+
+    // Each entry is (bucket upper bound, count); `None` marks the final,
+    // unbounded bucket.
+    fn snapshot(&self) -> Vec<(Option<usize>, u64)> {
+        SIZE_HISTOGRAM_BOUNDS
+            .into_iter()
+            .map(Some)
+            .chain(std::iter::once(None))
+            .zip(self.counts)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    added: u64,
+    duplicates_rejected: u64,
+    deleted: u64,
+    evicted: u64,
+}
+
+/// A point-in-time read of a `MessageStore`'s counters and gauges.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackerMetricsSnapshot {
+    /// Cumulative count of messages successfully stored.
+    pub added: u64,
+    /// Cumulative count of messages rejected as duplicates, resident or
+    /// recently seen.
+    pub duplicates_rejected: u64,
+    /// Cumulative count of messages removed by an explicit delete.
+    pub deleted: u64,
+    /// Cumulative count of messages removed automatically, by FIFO overflow
+    /// or TTL expiry.
+    pub evicted: u64,
+    /// Current number of live messages.
+    pub occupancy: usize,
+    /// Current number of live messages per peer_id.
+    pub peer_occupancy: HashMap<String, usize>,
+    /// Counts of stored messages' `data` sizes, bucketed by
+    /// `SIZE_HISTOGRAM_BOUNDS`; each entry is (bucket upper bound, count),
+    /// with `None` marking the final, unbounded bucket.
+    pub message_size_histogram: Vec<(Option<usize>, u64)>,
+}
+
+// Reason a slot was evicted, used to route the removal to the right
+// cumulative counter and sink callback.
+enum EvictReason {
+    Deleted,
+    Automatic,
+}
+
+/// Source of the current time, abstracted so expiry can be tested
+/// deterministically instead of depending on wall-clock sleeps.
+trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The real clock, used by `MessageStore` outside of tests.
+#[derive(Debug, Default, Clone, Copy)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A slot in the arena backing `MessageStore`. Slots form a doubly-linked
+/// list in FIFO order so `add`/`delete` never need to shift or renumber
+/// their neighbors. Slots also form a second, per-peer doubly-linked list
+/// (via `peer_prev`/`peer_next`) so a peer's messages can be unlinked in
+/// O(1) too, the same way the main FIFO list is.
+struct Node {
+    message: Message,
+    prev: Option<usize>,
+    next: Option<usize>,
+    peer_prev: Option<usize>,
+    peer_next: Option<usize>,
+    // Instant at which this message should be treated as gone. `None`
+    // means the message never expires on its own.
+    expiry: Option<Instant>,
+}
+
+// Head/tail slots of one peer's doubly-linked list, threaded through
+// `Node::peer_prev`/`peer_next`.
+#[derive(Clone, Copy)]
+struct PeerList {
+    head: usize,
+    tail: usize,
+}
+
+struct MessageStore<C: Clock = SystemClock> {
+    // Slab of nodes. Freed slots are recycled via `free` rather than
+    // removed, so indices into this vec are stable for the lifetime of
+    // the slot.
+    nodes: Vec<Node>,
+    // Mapping from message ID to its slot in `nodes`, works as a cache
     index: HashMap<String, usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
+    free: Vec<usize>,
+    len: usize,
     fifo_size: usize,
+    clock: C,
+    // Live message count per peer_id, kept in sync on insert, overflow
+    // eviction and delete. `None` limit means peers are unbounded.
+    peer_counts: HashMap<String, usize>,
+    per_peer_limit: Option<usize>,
+    // Secondary index from peer_id to that peer's slot list (threaded
+    // through `Node::peer_prev`/`peer_next`), kept in sync alongside
+    // `index` so `get_by_peer`, `delete_by_peer` and peer unlinking don't
+    // need a full scan of `nodes`. `get_all_filtered` does not use it and
+    // walks the whole main FIFO.
+    peer_index: HashMap<String, PeerList>,
+    // Bounded FIFO of fingerprints of recently accepted messages, consulted
+    // on `add` so a message that already left the main queue (or a
+    // byte-identical one under `ByContentHash`) is still rejected as a
+    // duplicate. `None` disables the seen-cache entirely.
+    seen_cache: VecDeque<Fingerprint>,
+    seen_set: HashSet<Fingerprint>,
+    seen_cache_size: Option<usize>,
+    dedup_mode: DedupMode,
+    counters: Counters,
+    size_histogram: SizeHistogram,
+    metrics_sink: Box<dyn MetricsSink>,
 }
 
-impl MessageStore {
+impl MessageStore<SystemClock> {
     #[allow(dead_code)]
     fn new(fifo_size: usize) -> Self {
+        MessageStore::with_clock(fifo_size, SystemClock)
+    }
+}
+
+impl<C: Clock> MessageStore<C> {
+    /// Rejects messages from a peer that already has `per_peer_limit`
+    /// messages stored, instead of letting one noisy peer dominate the
+    /// whole tracker. Combinable with `with_seen_cache`/`with_metrics`.
+    #[allow(dead_code)]
+    fn with_peer_limit(mut self, per_peer_limit: usize) -> Self {
+        self.per_peer_limit = Some(per_peer_limit);
+        self
+    }
+
+    /// Also rejects a message whose fingerprint (by `mode`) was accepted
+    /// within the last `seen_cache_size` accepted messages, even if it is
+    /// no longer resident in the FIFO. Combinable with
+    /// `with_peer_limit`/`with_metrics`.
+    #[allow(dead_code)]
+    fn with_seen_cache(mut self, seen_cache_size: usize, mode: DedupMode) -> Self {
+        self.seen_cache_size = Some(seen_cache_size);
+        self.dedup_mode = mode;
+        self
+    }
+
+    /// Also pushes every counter update to `sink` as it happens, e.g. to
+    /// bridge into `prometheus-client`. Use `metrics` for a pull-style
+    /// snapshot instead. Combinable with
+    /// `with_peer_limit`/`with_seen_cache`.
+    #[allow(dead_code)]
+    fn with_metrics(mut self, sink: impl MetricsSink + 'static) -> Self {
+        self.metrics_sink = Box::new(sink);
+        self
+    }
+
+    #[allow(dead_code)]
+    fn with_clock(fifo_size: usize, clock: C) -> Self {
         MessageStore {
-            queue: VecDeque::new(),
+            nodes: Vec::new(),
             index: HashMap::new(),
+            head: None,
+            tail: None,
+            free: Vec::new(),
+            len: 0,
             fifo_size,
+            clock,
+            peer_counts: HashMap::new(),
+            per_peer_limit: None,
+            peer_index: HashMap::new(),
+            seen_cache: VecDeque::new(),
+            seen_set: HashSet::new(),
+            seen_cache_size: None,
+            dedup_mode: DedupMode::ById,
+            counters: Counters::default(),
+            size_histogram: SizeHistogram::default(),
+            metrics_sink: Box::new(NoopMetricsSink),
+        }
+    }
+
+    /// Returns a point-in-time snapshot of this store's counters and
+    /// gauges. Sweeps expired entries first, so `occupancy` and
+    /// `peer_occupancy` never count messages that are stale but haven't
+    /// yet been evicted by a `get`/`get_all`/`prune_expired` call.
+    #[allow(dead_code)]
+    fn metrics(&mut self) -> TrackerMetricsSnapshot {
+        self.prune_expired();
+
+        TrackerMetricsSnapshot {
+            added: self.counters.added,
+            duplicates_rejected: self.counters.duplicates_rejected,
+            deleted: self.counters.deleted,
+            evicted: self.counters.evicted,
+            occupancy: self.len,
+            peer_occupancy: self.peer_counts.clone(),
+            message_size_histogram: self.size_histogram.snapshot(),
+        }
+    }
+
+    /// Add will add a message to the tracker with a given TTL, deleting
+    /// the oldest message if necessary. The message is treated as absent
+    /// once `ttl` has elapsed, even if it has not yet been swept by
+    /// `prune_expired`.
+    #[allow(dead_code)]
+    fn add_with_ttl(&mut self, message: Message, ttl: Duration) -> AddOutcome {
+        let deadline = self.clock.now() + ttl;
+        self.insert(message, Some(deadline))
+    }
+
+    /// Removes and returns every message whose TTL deadline has passed.
+    /// Messages without a TTL are never pruned.
+    #[allow(dead_code)]
+    fn prune_expired(&mut self) -> Vec<Message> {
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        let mut current = self.head;
+
+        while let Some(slot) = current {
+            let next = self.nodes[slot].next;
+            if self.nodes[slot]
+                .expiry
+                .is_some_and(|deadline| deadline <= now)
+            {
+                expired.push(self.evict_slot(slot, EvictReason::Automatic));
+            }
+            current = next;
         }
+
+        expired
     }
 
-    fn update_indices(&mut self, skip: usize) {
-        for (index, message) in self.queue.iter().skip(skip).enumerate() {
-            self.index.insert(message.id.clone(), index + skip);
+    // Like `prune_expired`, but scoped to a single peer's own linked list,
+    // so the per-peer quota check doesn't count slots that are expired but
+    // not yet evicted.
+    fn prune_expired_for_peer(&mut self, peer_id: &str) {
+        let mut current = self.peer_index.get(peer_id).map(|list| list.head);
+
+        while let Some(slot) = current {
+            let next = self.nodes[slot].peer_next;
+            if self.is_expired(slot) {
+                self.evict_slot(slot, EvictReason::Automatic);
+            }
+            current = next;
         }
     }
 
     fn exists(&self, id: &str) -> bool {
-        self.get(id).is_some()
+        self.index.contains_key(id)
     }
-}
 
-impl MessageTracker for MessageStore {
-    fn add(&mut self, message: Message) {
+    fn is_expired(&self, slot: usize) -> bool {
+        self.nodes[slot]
+            .expiry
+            .is_some_and(|deadline| deadline <= self.clock.now())
+    }
+
+    fn fingerprint(&self, message: &Message) -> Fingerprint {
+        match self.dedup_mode {
+            DedupMode::ById => Fingerprint::Id(message.id.clone()),
+            DedupMode::ByContentHash => {
+                let mut hasher = DefaultHasher::new();
+                message.data.hash(&mut hasher);
+                Fingerprint::ContentHash(hasher.finish())
+            }
+        }
+    }
+
+    // Records `fingerprint` as seen, evicting the oldest recorded
+    // fingerprint once the configured seen-cache size is exceeded. A no-op
+    // when the seen-cache is disabled.
+    fn record_seen(&mut self, fingerprint: Fingerprint) {
+        let Some(limit) = self.seen_cache_size.filter(|&limit| limit > 0) else {
+            return;
+        };
+
+        if self.seen_set.insert(fingerprint.clone()) {
+            self.seen_cache.push_back(fingerprint);
+            // The cache just grew by one, so it can only be over `limit`
+            // by one entry, and can't be empty.
+            if self.seen_cache.len() > limit {
+                let oldest = self
+                    .seen_cache
+                    .pop_front()
+                    .expect("seen_cache is non-empty when over limit");
+                self.seen_set.remove(&oldest);
+            }
+        }
+    }
+
+    fn insert(&mut self, message: Message, expiry: Option<Instant>) -> AddOutcome {
         let message_id = message.id.clone();
+        let fingerprint = self.fingerprint(&message);
+
+        // An expired-but-unswept copy of this ID is already gone as far as
+        // callers are concerned, so sweep it instead of reporting a duplicate.
+        if let Some(&slot) = self.index.get(&message_id) {
+            if self.is_expired(slot) {
+                self.evict_slot(slot, EvictReason::Automatic);
+            }
+        }
 
         if self.exists(&message_id) {
-            return;
+            self.counters.duplicates_rejected += 1;
+            self.metrics_sink.on_duplicate();
+            return AddOutcome::Duplicate;
         }
 
-        // Enqueue message at the back of the queue
-        self.queue.push_back(message);
+        if self.seen_set.contains(&fingerprint) {
+            self.counters.duplicates_rejected += 1;
+            self.metrics_sink.on_duplicate();
+            return AddOutcome::RecentlySeen;
+        }
 
-        // If the queue size exceeds the configured FIFO size
-        // Remove the oldest message
-        if self.queue.len() > self.fifo_size {
-            if let Some(removed_message) = self.queue.pop_front() {
-                // Remove the oldest message from the indices cache
-                self.index.remove(&removed_message.id);
-                // Update indices cache for all elements
-                self.update_indices(0);
+        if let Some(limit) = self.per_peer_limit {
+            // Expired-but-unswept slots shouldn't count against the quota,
+            // the same way `metrics()` prunes before reading its gauges.
+            self.prune_expired_for_peer(&message.peer_id);
+            let count = self.peer_counts.get(&message.peer_id).copied().unwrap_or(0);
+            if count >= limit {
+                return AddOutcome::PeerQuotaExceeded;
             }
+        }
+
+        let peer_id = message.peer_id.clone();
+        let data_len = message.data.len();
+        let peer_tail = self.peer_index.get(&peer_id).map(|list| list.tail);
+
+        // Link the new node in at the tail of both the main FIFO and the
+        // peer's own FIFO.
+        let node = Node {
+            message,
+            prev: self.tail,
+            next: None,
+            peer_prev: peer_tail,
+            peer_next: None,
+            expiry,
+        };
+        let slot = self.alloc(node);
+
+        match self.tail {
+            Some(t) => self.nodes[t].next = Some(slot),
+            None => self.head = Some(slot),
+        }
+        self.tail = Some(slot);
+
+        if let Some(t) = peer_tail {
+            self.nodes[t].peer_next = Some(slot);
+        }
+        self.peer_index
+            .entry(peer_id.clone())
+            .and_modify(|list| list.tail = slot)
+            .or_insert(PeerList {
+                head: slot,
+                tail: slot,
+            });
+
+        self.index.insert(message_id, slot);
+        self.len += 1;
+        *self.peer_counts.entry(peer_id.clone()).or_insert(0) += 1;
+        self.record_seen(fingerprint);
+
+        self.counters.added += 1;
+        self.size_histogram.record(data_len);
+        self.metrics_sink.on_added(&peer_id, data_len);
+
+        // If the queue size exceeds the configured FIFO size, evict the
+        // oldest message. `head` is always set at this point: we just
+        // linked a node in above, so the FIFO can't be empty.
+        if self.len > self.fifo_size {
+            let head_slot = self.head.expect("head is set after an insert");
+            return AddOutcome::Evicted(self.evict_slot(head_slot, EvictReason::Automatic));
+        }
+
+        AddOutcome::Stored
+    }
+
+    // Places `node` into a recycled slot if one is free, otherwise grows
+    // the arena. Returns the slot the node now occupies.
+    fn alloc(&mut self, node: Node) -> usize {
+        if let Some(slot) = self.free.pop() {
+            self.nodes[slot] = node;
+            slot
         } else {
-            // Update the indices cache with the queue position of the new message
-            self.index.insert(message_id, self.queue.len() - 1);
+            self.nodes.push(node);
+            self.nodes.len() - 1
         }
     }
 
-    fn delete(&mut self, id: &str) -> Option<Message> {
-        // Check if the message_id exists in the index
-        match self.index.remove(id) {
-            None => None,
-            Some(queue_index) => {
-                // Remove the message from the queue using the index
-                if let Some(removed_message) = self.queue.remove(queue_index) {
-                    // Update the index for the remaining messages
-                    self.update_indices(queue_index);
-                    Some(removed_message)
-                } else {
-                    panic!("It should never happen")
+    // Unlinks the node at `slot` from the FIFO, fixing up its neighbors'
+    // `prev`/`next` pointers and `head`/`tail` as needed. Does not recycle
+    // the slot itself.
+    fn unlink(&mut self, slot: usize) {
+        let (prev, next) = {
+            let node = &self.nodes[slot];
+            (node.prev, node.next)
+        };
+
+        match prev {
+            Some(p) => self.nodes[p].next = next,
+            None => self.head = next,
+        }
+
+        match next {
+            Some(n) => self.nodes[n].prev = prev,
+            None => self.tail = prev,
+        }
+    }
+
+    // Removes the node at `slot` from the index and the FIFO, recycles
+    // its slot, updates the metrics for `reason`, and returns the message
+    // it held.
+    fn evict_slot(&mut self, slot: usize, reason: EvictReason) -> Message {
+        let message = self.nodes[slot].message.clone();
+        self.index.remove(&message.id);
+        self.unlink(slot);
+        self.free.push(slot);
+        self.len -= 1;
+        self.decrement_peer_count(&message.peer_id);
+        self.remove_from_peer_index(&message.peer_id, slot);
+
+        match reason {
+            EvictReason::Deleted => {
+                self.counters.deleted += 1;
+                self.metrics_sink.on_deleted(&message.peer_id);
+            }
+            EvictReason::Automatic => {
+                self.counters.evicted += 1;
+                self.metrics_sink.on_evicted(&message.peer_id);
+            }
+        }
+
+        message
+    }
+
+    fn decrement_peer_count(&mut self, peer_id: &str) {
+        if let Some(count) = self.peer_counts.get_mut(peer_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.peer_counts.remove(peer_id);
+            }
+        }
+    }
+
+    // Unlinks `slot` from `peer_id`'s own doubly-linked list, fixing up its
+    // neighbors' `peer_prev`/`peer_next` and the peer's head/tail the same
+    // way `unlink` does for the main FIFO, so this is O(1) regardless of
+    // how many messages the peer has resident.
+    fn remove_from_peer_index(&mut self, peer_id: &str, slot: usize) {
+        let (peer_prev, peer_next) = {
+            let node = &self.nodes[slot];
+            (node.peer_prev, node.peer_next)
+        };
+
+        if peer_prev.is_none() && peer_next.is_none() {
+            self.peer_index.remove(peer_id);
+            return;
+        }
+
+        match peer_prev {
+            Some(p) => self.nodes[p].peer_next = peer_next,
+            None => {
+                if let Some(list) = self.peer_index.get_mut(peer_id) {
+                    list.head = peer_next.expect("peer list has more than one node");
+                }
+            }
+        }
+
+        match peer_next {
+            Some(n) => self.nodes[n].peer_prev = peer_prev,
+            None => {
+                if let Some(list) = self.peer_index.get_mut(peer_id) {
+                    list.tail = peer_prev.expect("peer list has more than one node");
                 }
             }
         }
     }
+}
+
+impl<C: Clock> MessageTracker for MessageStore<C> {
+    fn add(&mut self, message: Message) -> AddOutcome {
+        self.insert(message, None)
+    }
+
+    fn delete(&mut self, id: &str) -> Option<Message> {
+        let slot = *self.index.get(id)?;
+
+        if self.is_expired(slot) {
+            self.evict_slot(slot, EvictReason::Automatic);
+            return None;
+        }
+
+        Some(self.evict_slot(slot, EvictReason::Deleted))
+    }
+
+    fn get(&mut self, id: &str) -> Option<Message> {
+        let slot = match self.index.get(id) {
+            Some(&slot) => slot,
+            None => return None,
+        };
+
+        if self.is_expired(slot) {
+            self.evict_slot(slot, EvictReason::Automatic);
+            return None;
+        }
+
+        let message = self.nodes[slot].message.clone();
+        if message.id != id {
+            panic!("Bad cache value for message: {id}");
+        }
+        Some(message)
+    }
+
+    fn get_all(&mut self) -> Vec<Message> {
+        let mut result = Vec::with_capacity(self.len);
+        let mut current = self.head;
+
+        while let Some(slot) = current {
+            let next = self.nodes[slot].next;
+            if self.is_expired(slot) {
+                self.evict_slot(slot, EvictReason::Automatic);
+            } else {
+                result.push(self.nodes[slot].message.clone());
+            }
+            current = next;
+        }
+
+        result
+    }
+
+    fn get_by_peer(&self, peer_id: &str) -> Vec<Message> {
+        let mut result = Vec::new();
+        let mut current = self.peer_index.get(peer_id).map(|list| list.head);
 
-    fn get(&self, id: &str) -> Option<Message> {
-        // Check if the message_id exists in the index
-        let msg = self
-            .index
-            .get(id)
-            .map(|&queue_index| self.queue[queue_index].clone());
-        match msg {
-            Some(m) if m.id != id => panic!("Bad cache value for message: {id}"),
-            _ => msg,
+        while let Some(slot) = current {
+            let node = &self.nodes[slot];
+            if !self.is_expired(slot) {
+                result.push(node.message.clone());
+            }
+            current = node.peer_next;
         }
+
+        result
     }
 
-    fn get_all(&self) -> Vec<Message> {
-        self.queue.clone().into()
+    fn delete_by_peer(&mut self, peer_id: &str) -> Vec<Message> {
+        let mut result = Vec::new();
+        let mut current = self.peer_index.get(peer_id).map(|list| list.head);
+
+        while let Some(slot) = current {
+            let next = self.nodes[slot].peer_next;
+            if self.is_expired(slot) {
+                self.evict_slot(slot, EvictReason::Automatic);
+            } else {
+                result.push(self.evict_slot(slot, EvictReason::Deleted));
+            }
+            current = next;
+        }
+
+        result
+    }
+
+    fn get_all_filtered(&self, peers: &PeerFilter) -> Vec<Message> {
+        let mut result = Vec::new();
+        let mut current = self.head;
+
+        while let Some(slot) = current {
+            let node = &self.nodes[slot];
+            if !self.is_expired(slot) && peers.matches(&node.message.peer_id) {
+                result.push(node.message.clone());
+            }
+            current = node.next;
+        }
+
+        result
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
     use crate::network::message::Message;
 
-    use super::{MessageStore, MessageTracker};
+    use std::collections::HashSet;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use super::{
+        AddOutcome, Clock, DedupMode, MessageStore, MessageTracker, MetricsSink, PeerFilter,
+    };
+
+    /// A clock that only advances when told to, so TTL tests are
+    /// deterministic instead of racing the wall clock.
+    struct MockClock {
+        now: Cell<Instant>,
+    }
+
+    impl MockClock {
+        fn new() -> Self {
+            MockClock {
+                now: Cell::new(Instant::now()),
+            }
+        }
+
+        fn advance(&self, delta: Duration) {
+            self.now.set(self.now.get() + delta);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+    }
 
     fn generate_msg_id(n: usize) -> String {
         format!("someID{}", n)
@@ -297,7 +937,7 @@ mod test {
     #[test]
     fn empty_tracker_get() {
         let length = 5;
-        let mt = get_tracker(length);
+        let mut mt = get_tracker(length);
         assert!(mt.get("bleh").is_none());
     }
 
@@ -348,4 +988,450 @@ mod test {
             ]
         )
     }
+
+    #[test]
+    fn ttl_expiry_is_hidden_from_get_before_sweep() {
+        let clock = MockClock::new();
+        let mut mt = MessageStore::with_clock(5, clock);
+
+        mt.add_with_ttl(generate_message(0), Duration::from_secs(10));
+        assert!(mt.get(&generate_msg_id(0)).is_some());
+
+        mt.clock.advance(Duration::from_secs(11));
+
+        // Lazily evicted on access, without waiting for a sweep
+        assert!(mt.get(&generate_msg_id(0)).is_none());
+        assert!(mt.get_all().is_empty());
+    }
+
+    #[test]
+    fn ttl_expiry_is_hidden_from_delete() {
+        let clock = MockClock::new();
+        let mut mt = MessageStore::with_clock(5, clock);
+
+        mt.add_with_ttl(generate_message(0), Duration::from_secs(10));
+        mt.clock.advance(Duration::from_secs(11));
+
+        // The message is already stale, so delete must report it as gone
+        // rather than handing back the expired payload.
+        assert!(mt.delete(&generate_msg_id(0)).is_none());
+
+        let snapshot = mt.metrics();
+        assert_eq!(snapshot.evicted, 1);
+        assert_eq!(snapshot.deleted, 0);
+    }
+
+    #[test]
+    fn ttl_expiry_is_hidden_from_delete_by_peer() {
+        let clock = MockClock::new();
+        let mut mt = MessageStore::with_clock(5, clock);
+
+        let peer = generate_peer_id(0);
+        let mut expired = generate_message(0);
+        expired.peer_id = peer.clone();
+        mt.add_with_ttl(expired, Duration::from_secs(1));
+
+        let mut fresh = generate_message(1);
+        fresh.peer_id = peer.clone();
+        mt.add(fresh.clone());
+
+        mt.clock.advance(Duration::from_secs(10));
+
+        // Only the still-live message is returned; the expired one is
+        // swept but not reported as deleted.
+        assert_eq!(mt.delete_by_peer(&peer), vec![fresh]);
+
+        let snapshot = mt.metrics();
+        assert_eq!(snapshot.evicted, 1);
+        assert_eq!(snapshot.deleted, 1);
+    }
+
+    #[test]
+    fn ttl_expiry_does_not_affect_messages_without_a_ttl() {
+        let clock = MockClock::new();
+        let mut mt = MessageStore::with_clock(5, clock);
+
+        mt.add(generate_message(0));
+        mt.add_with_ttl(generate_message(1), Duration::from_secs(10));
+
+        mt.clock.advance(Duration::from_secs(11));
+
+        assert_eq!(mt.get_all(), vec![generate_message(0)]);
+    }
+
+    #[test]
+    fn prune_expired_removes_and_returns_stale_messages() {
+        let clock = MockClock::new();
+        let mut mt = MessageStore::with_clock(5, clock);
+
+        mt.add_with_ttl(generate_message(0), Duration::from_secs(5));
+        mt.add_with_ttl(generate_message(1), Duration::from_secs(20));
+        mt.add(generate_message(2));
+
+        mt.clock.advance(Duration::from_secs(10));
+
+        let pruned = mt.prune_expired();
+        assert_eq!(pruned, vec![generate_message(0)]);
+        assert_eq!(mt.get_all(), vec![generate_message(1), generate_message(2)]);
+
+        assert!(mt.prune_expired().is_empty());
+    }
+
+    #[test]
+    fn add_outcome_reports_stored_duplicate_and_eviction() {
+        let mut mt = MessageStore::new(2);
+
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::Stored);
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::Duplicate);
+        assert_eq!(mt.add(generate_message(1)), AddOutcome::Stored);
+        assert_eq!(
+            mt.add(generate_message(2)),
+            AddOutcome::Evicted(generate_message(0))
+        );
+    }
+
+    #[test]
+    fn per_peer_limit_rejects_once_quota_is_reached() {
+        let mut mt = MessageStore::new(10).with_peer_limit(2);
+
+        let noisy_peer = generate_peer_id(0);
+        for i in 0..2 {
+            let mut msg = generate_message(i);
+            msg.peer_id = noisy_peer.clone();
+            assert_eq!(mt.add(msg), AddOutcome::Stored);
+        }
+
+        let mut rejected = generate_message(2);
+        rejected.peer_id = noisy_peer;
+        assert_eq!(mt.add(rejected), AddOutcome::PeerQuotaExceeded);
+
+        // A different peer is unaffected by the first peer's quota
+        assert_eq!(mt.add(generate_message(3)), AddOutcome::Stored);
+        assert_eq!(mt.get_all().len(), 3);
+    }
+
+    #[test]
+    fn per_peer_count_frees_up_after_delete_and_eviction() {
+        let mut mt = MessageStore::new(2).with_peer_limit(1);
+
+        let peer = generate_peer_id(0);
+        let mut first = generate_message(0);
+        first.peer_id = peer.clone();
+        assert_eq!(mt.add(first.clone()), AddOutcome::Stored);
+
+        let mut second = generate_message(1);
+        second.peer_id = peer.clone();
+        assert_eq!(mt.add(second), AddOutcome::PeerQuotaExceeded);
+
+        // Deleting the peer's message should make room again
+        mt.delete(&generate_msg_id(0));
+        let mut third = generate_message(2);
+        third.peer_id = peer.clone();
+        assert_eq!(mt.add(third.clone()), AddOutcome::Stored);
+
+        // Filling the FIFO with another peer's message, then overflowing it,
+        // evicts the peer's message and frees its quota again
+        assert_eq!(mt.add(generate_message(3)), AddOutcome::Stored);
+        assert_eq!(mt.add(generate_message(4)), AddOutcome::Evicted(third));
+
+        let mut fifth = generate_message(5);
+        fifth.peer_id = peer;
+        assert_ne!(mt.add(fifth), AddOutcome::PeerQuotaExceeded);
+    }
+
+    #[test]
+    fn per_peer_quota_ignores_expired_but_unswept_messages() {
+        let clock = MockClock::new();
+        let mut mt = MessageStore::with_clock(10, clock).with_peer_limit(1);
+
+        let peer = generate_peer_id(0);
+        let mut first = generate_message(0);
+        first.peer_id = peer.clone();
+        assert_eq!(
+            mt.add_with_ttl(first, Duration::from_secs(1)),
+            AddOutcome::Stored
+        );
+
+        mt.clock.advance(Duration::from_secs(10));
+
+        // The quota-occupying message is long expired, and nothing has
+        // called get/get_all/prune_expired to sweep it, but a second
+        // message for the same peer must still be accepted.
+        let mut second = generate_message(1);
+        second.peer_id = peer;
+        assert_eq!(mt.add(second), AddOutcome::Stored);
+    }
+
+    #[test]
+    fn get_by_peer_returns_only_that_peers_messages_in_fifo_order() {
+        let mut mt = MessageStore::new(10);
+
+        let shared_peer = generate_peer_id(0);
+        let mut expected = Vec::new();
+        for i in 0..3 {
+            let mut msg = generate_message(i);
+            msg.peer_id = shared_peer.clone();
+            mt.add(msg.clone());
+            expected.push(msg);
+        }
+        mt.add(generate_message(3));
+
+        assert_eq!(mt.get_by_peer(&shared_peer), expected);
+        assert!(mt.get_by_peer("no-such-peer").is_empty());
+    }
+
+    #[test]
+    fn get_by_peer_stays_in_order_after_deleting_a_middle_message() {
+        let mut mt = MessageStore::new(10);
+
+        let shared_peer = generate_peer_id(0);
+        let mut messages = Vec::new();
+        for i in 0..3 {
+            let mut msg = generate_message(i);
+            msg.peer_id = shared_peer.clone();
+            mt.add(msg.clone());
+            messages.push(msg);
+        }
+
+        // Deleting the peer's middle message must leave its neighbors'
+        // per-peer links intact, not just the main FIFO's.
+        mt.delete(&generate_msg_id(1));
+
+        assert_eq!(
+            mt.get_by_peer(&shared_peer),
+            vec![messages[0].clone(), messages[2].clone()]
+        );
+    }
+
+    #[test]
+    fn delete_by_peer_purges_only_that_peers_messages() {
+        let mut mt = MessageStore::new(10);
+
+        let misbehaving_peer = generate_peer_id(0);
+        let mut first = generate_message(0);
+        first.peer_id = misbehaving_peer.clone();
+        let mut second = generate_message(1);
+        second.peer_id = misbehaving_peer.clone();
+        mt.add(first.clone());
+        mt.add(second.clone());
+        mt.add(generate_message(2));
+
+        let deleted = mt.delete_by_peer(&misbehaving_peer);
+        assert_eq!(deleted, vec![first, second]);
+        assert_eq!(mt.get_all(), vec![generate_message(2)]);
+        assert!(mt.get_by_peer(&misbehaving_peer).is_empty());
+
+        // Purging again is a no-op
+        assert!(mt.delete_by_peer(&misbehaving_peer).is_empty());
+    }
+
+    #[test]
+    fn get_all_filtered_applies_only_and_except() {
+        let mut mt = MessageStore::new(10);
+
+        let trusted = generate_peer_id(0);
+        let untrusted = generate_peer_id(1);
+
+        let mut from_trusted = generate_message(0);
+        from_trusted.peer_id = trusted.clone();
+        let mut from_untrusted = generate_message(1);
+        from_untrusted.peer_id = untrusted.clone();
+        mt.add(from_trusted.clone());
+        mt.add(from_untrusted.clone());
+
+        let only_trusted = PeerFilter::Only(HashSet::from([trusted.clone()]));
+        assert_eq!(mt.get_all_filtered(&only_trusted), vec![from_trusted]);
+
+        let mut expected = generate_message(0);
+        expected.peer_id = trusted;
+        let except_untrusted = PeerFilter::Except(HashSet::from([untrusted]));
+        assert_eq!(mt.get_all_filtered(&except_untrusted), vec![expected]);
+    }
+
+    #[test]
+    fn seen_cache_rejects_reinsertion_after_eviction() {
+        let mut mt = MessageStore::new(2).with_seen_cache(5, DedupMode::ById);
+
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::Stored);
+        assert_eq!(mt.add(generate_message(1)), AddOutcome::Stored);
+        assert_eq!(
+            mt.add(generate_message(2)),
+            AddOutcome::Evicted(generate_message(0))
+        );
+
+        // generate_message(0) is no longer resident, but its fingerprint is
+        // still in the seen-cache
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::RecentlySeen);
+        assert!(mt.get(&generate_msg_id(0)).is_none());
+    }
+
+    #[test]
+    fn still_resident_duplicate_is_not_reported_as_recently_seen() {
+        let mut mt = MessageStore::new(5).with_seen_cache(5, DedupMode::ById);
+
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::Stored);
+        // Still resident in the main queue, so this is a Duplicate, not a
+        // RecentlySeen rejection, even though its fingerprint is also in
+        // the seen-cache.
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::Duplicate);
+    }
+
+    #[test]
+    fn seen_cache_is_bounded_and_forgets_old_fingerprints() {
+        let mut mt = MessageStore::new(5).with_seen_cache(2, DedupMode::ById);
+
+        for i in 0..4 {
+            assert_eq!(mt.add(generate_message(i)), AddOutcome::Stored);
+        }
+        mt.delete(&generate_msg_id(0));
+
+        // Only the 2 most recent fingerprints (2, 3) are still remembered
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::Stored);
+    }
+
+    #[test]
+    fn content_hash_dedup_mode_rejects_byte_identical_payloads_with_new_ids() {
+        let mut mt = MessageStore::new(5).with_seen_cache(5, DedupMode::ByContentHash);
+
+        let mut first = generate_message(0);
+        first.data = vec![42, 42];
+        assert_eq!(mt.add(first), AddOutcome::Stored);
+
+        let mut resubmitted = generate_message(1);
+        resubmitted.data = vec![42, 42];
+        assert_eq!(mt.add(resubmitted), AddOutcome::RecentlySeen);
+
+        let mut distinct = generate_message(2);
+        distinct.data = vec![7];
+        assert_eq!(mt.add(distinct), AddOutcome::Stored);
+    }
+
+    #[test]
+    fn metrics_snapshot_tracks_counters_and_gauges() {
+        let mut mt = MessageStore::new(2);
+
+        mt.add(generate_message(0));
+        mt.add(generate_message(1));
+        mt.add(generate_message(0)); // duplicate
+        mt.add(generate_message(2)); // overflow-evicts message 0
+        mt.delete(&generate_msg_id(1));
+
+        let snapshot = mt.metrics();
+        assert_eq!(snapshot.added, 3);
+        assert_eq!(snapshot.duplicates_rejected, 1);
+        assert_eq!(snapshot.evicted, 1);
+        assert_eq!(snapshot.deleted, 1);
+        assert_eq!(snapshot.occupancy, 1);
+        assert_eq!(snapshot.peer_occupancy.get(&generate_peer_id(2)), Some(&1));
+        let total_histogram_count: u64 =
+            snapshot.message_size_histogram.iter().map(|(_, c)| c).sum();
+        assert_eq!(total_histogram_count, 3);
+    }
+
+    #[test]
+    fn metrics_occupancy_excludes_expired_messages_before_any_sweep() {
+        let clock = MockClock::new();
+        let mut mt = MessageStore::with_clock(5, clock);
+
+        mt.add_with_ttl(generate_message(0), Duration::from_secs(5));
+        mt.clock.advance(Duration::from_secs(10));
+
+        // Nothing has called get/get_all/prune_expired yet, but the
+        // snapshot must not report a message that is already stale.
+        let snapshot = mt.metrics();
+        assert_eq!(snapshot.occupancy, 0);
+        assert!(snapshot.peer_occupancy.is_empty());
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        added: AtomicUsize,
+        duplicates: AtomicUsize,
+        deleted: AtomicUsize,
+        evicted: AtomicUsize,
+        last_added_peer: Mutex<Option<String>>,
+    }
+
+    impl MetricsSink for RecordingSink {
+        fn on_added(&self, peer_id: &str, _size: usize) {
+            self.added.fetch_add(1, Ordering::SeqCst);
+            *self.last_added_peer.lock().unwrap() = Some(peer_id.to_string());
+        }
+
+        fn on_duplicate(&self) {
+            self.duplicates.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_deleted(&self, _peer_id: &str) {
+            self.deleted.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_evicted(&self, _peer_id: &str) {
+            self.evicted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl MetricsSink for std::sync::Arc<RecordingSink> {
+        fn on_added(&self, peer_id: &str, size: usize) {
+            self.as_ref().on_added(peer_id, size);
+        }
+        fn on_duplicate(&self) {
+            self.as_ref().on_duplicate();
+        }
+        fn on_deleted(&self, peer_id: &str) {
+            self.as_ref().on_deleted(peer_id);
+        }
+        fn on_evicted(&self, peer_id: &str) {
+            self.as_ref().on_evicted(peer_id);
+        }
+    }
+
+    #[test]
+    fn metrics_sink_is_notified_on_every_path() {
+        // The sink lives behind `Box<dyn MetricsSink>` inside the store, so
+        // assertions read back through a shared handle instead.
+        let sink = std::sync::Arc::new(RecordingSink::default());
+
+        let mut mt = MessageStore::new(2).with_metrics(sink.clone());
+
+        mt.add(generate_message(0));
+        mt.add(generate_message(1));
+        mt.add(generate_message(0)); // duplicate
+        mt.add(generate_message(2)); // overflow-evicts message 0
+        mt.delete(&generate_msg_id(1));
+
+        assert_eq!(sink.added.load(Ordering::SeqCst), 3);
+        assert_eq!(sink.duplicates.load(Ordering::SeqCst), 1);
+        assert_eq!(sink.evicted.load(Ordering::SeqCst), 1);
+        assert_eq!(sink.deleted.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            *sink.last_added_peer.lock().unwrap(),
+            Some(generate_peer_id(2))
+        );
+    }
+
+    #[test]
+    fn builder_methods_combine_on_a_single_store() {
+        let sink = std::sync::Arc::new(RecordingSink::default());
+        let mut mt = MessageStore::new(10)
+            .with_peer_limit(1)
+            .with_seen_cache(5, DedupMode::ById)
+            .with_metrics(sink.clone());
+
+        let peer = generate_peer_id(0);
+        let mut first = generate_message(0);
+        first.peer_id = peer.clone();
+        assert_eq!(mt.add(first), AddOutcome::Stored);
+
+        let mut second = generate_message(1);
+        second.peer_id = peer;
+        assert_eq!(mt.add(second), AddOutcome::PeerQuotaExceeded);
+
+        mt.delete(&generate_msg_id(0));
+        // Still rejected: the seen-cache remembers it even though the
+        // per-peer quota has freed up again.
+        assert_eq!(mt.add(generate_message(0)), AddOutcome::RecentlySeen);
+
+        assert_eq!(sink.added.load(Ordering::SeqCst), 1);
+    }
 }